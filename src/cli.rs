@@ -16,4 +16,9 @@ pub struct Cli {
     /// Address of the node to connect to
     #[arg(long)]
     pub connect: Option<SocketAddrV4>,
+
+    /// Additional address this node can be reached at (e.g. a NAT-mapped address),
+    /// advertised to peers during the `Register` handshake. May be repeated.
+    #[arg(long = "alt-addr")]
+    pub alt_addrs: Vec<SocketAddrV4>,
 }
@@ -1,50 +1,71 @@
+use std::collections::HashMap;
 use std::net::SocketAddrV4;
 use std::time::Duration;
 
+use bytes::Bytes;
 use clap::Parser;
+use futures::{SinkExt, StreamExt};
 use gossipers::cli::Cli;
 use gossipers::node::{Event, Message, Node, Trigger};
 
 use gossipers::telemetry::init_tracing;
-use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-
 use tokio::time::interval;
-use tokio::{io::AsyncWriteExt, net::TcpListener};
 use tokio_stream::wrappers::TcpListenerStream;
-use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tracing::{debug, info, warn};
 
+type Connection = Framed<tokio::net::TcpStream, LengthDelimitedCodec>;
+
+/// Sends messages over a pool of long-lived, length-delimited connections, one per
+/// destination, instead of opening a fresh socket for every message.
 struct TcpSender {
     rx: Receiver<Message>,
     tx: Sender<Event>,
+    connections: HashMap<SocketAddrV4, Connection>,
 }
 
 impl TcpSender {
     async fn run(&mut self) {
         while let Some(msg) = self.rx.recv().await {
-            match tokio::net::TcpStream::connect(msg.dst).await {
-                Ok(mut stream) => {
-                    let data = serde_json::to_vec(&msg).unwrap();
-                    debug!("Sending message {:#?}", msg);
-                    stream.write_all(&data).await.unwrap();
-                }
-                Err(err) => {
-                    warn!("Unable to connect to {}: {}", msg.dst, err);
-                    if self
-                        .tx
-                        .send(Event::Trigger(Trigger::Strike(msg.dst)))
-                        .await
-                        .is_err()
-                    {
-                        info!("Channel closed, sender exiting");
-                        return;
-                    };
-                }
+            if let Err(err) = self.send(&msg).await {
+                warn!("Unable to reach {}: {}", msg.dst, err);
+                self.connections.remove(&msg.dst);
+                if self
+                    .tx
+                    .send(Event::Trigger(Trigger::Strike(msg.dst)))
+                    .await
+                    .is_err()
+                {
+                    info!("Channel closed, sender exiting");
+                    return;
+                };
             }
         }
     }
+
+    async fn send(&mut self, msg: &Message) -> std::io::Result<()> {
+        let connection = match self.connections.get_mut(&msg.dst) {
+            Some(connection) => connection,
+            None => {
+                let stream = tokio::net::TcpStream::connect(msg.dst).await?;
+                self.connections
+                    .entry(msg.dst)
+                    .or_insert_with(|| Framed::new(stream, LengthDelimitedCodec::new()))
+            }
+        };
+        let data = serde_json::to_vec(msg).unwrap();
+        debug!("Sending message {:#?}", msg);
+        connection
+            .send(Bytes::from(data))
+            .await
+            .map_err(std::io::Error::other)
+    }
 }
+
+/// Accepts connections and decodes length-delimited frames from each of them, forever.
+/// Unlike a close-per-message protocol, a single connection can carry many messages.
 struct TcpReceiver {
     listener: TcpListenerStream,
     tx: Sender<Event>,
@@ -61,13 +82,25 @@ impl TcpReceiver {
     }
     pub async fn run(&mut self) {
         while let Some(stream) = self.listener.next().await {
-            let mut stream = stream.unwrap();
-            let mut buf = Vec::new();
-            stream.read_to_end(&mut buf).await.unwrap();
-            self.tx
-                .send(Event::Message(serde_json::from_slice(&buf).unwrap()))
-                .await
-                .unwrap();
+            let stream = stream.unwrap();
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                let mut frames = Framed::new(stream, LengthDelimitedCodec::new());
+                while let Some(frame) = frames.next().await {
+                    let frame = match frame {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            warn!("Connection closed: {}", err);
+                            return;
+                        }
+                    };
+                    let message = serde_json::from_slice(&frame).unwrap();
+                    if tx.send(Event::Message(message)).await.is_err() {
+                        info!("Channel closed, receiver exiting");
+                        return;
+                    }
+                }
+            });
         }
     }
 }
@@ -103,6 +136,7 @@ async fn main() -> anyhow::Result<()> {
     let mut sender = TcpSender {
         rx: sender_rx,
         tx: node_tx.clone(),
+        connections: HashMap::new(),
     };
     tokio::spawn(ticker(
         node_tx.clone(),
@@ -111,10 +145,12 @@ async fn main() -> anyhow::Result<()> {
     ));
     tokio::spawn(ticker(node_tx.clone(), 1, Trigger::GossipSuspects));
     tokio::spawn(ticker(node_tx.clone(), 10, Trigger::CheckReplies));
+    tokio::spawn(ticker(node_tx.clone(), 5, Trigger::AntiEntropy));
+    tokio::spawn(ticker(node_tx.clone(), 30, Trigger::SweepMembership));
     tokio::spawn(async move { receiver.run().await });
     tokio::spawn(async move { sender.run().await });
 
-    let mut node = Node::new(addr, node_rx, sender_tx);
+    let mut node = Node::new(addr, args.alt_addrs.clone(), node_rx, sender_tx);
     node.main_loop().await;
     Ok(())
 }
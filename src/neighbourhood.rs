@@ -1,8 +1,74 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     net::SocketAddrV4,
+    time::{Duration, Instant},
 };
 
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Default number of peers to gossip with per round, regardless of how many
+/// neighbours are online.
+pub const DEFAULT_FANOUT: usize = 10;
+
+/// Charge count past which a neighbour's gossip weight bottoms out at its
+/// minimum, but doesn't reach zero - even shaky peers are occasionally probed.
+const MAX_CHARGES: u32 = 3;
+
+/// Maximum number of candidate endpoints remembered per neighbour. Oldest
+/// endpoints are evicted once a neighbour accumulates more than this.
+const MAX_ENDPOINTS: usize = 5;
+
+/// How long a neighbour gets to have at least one endpoint succeed before all
+/// recorded failures are forgotten and the retry count starts over.
+const ENDPOINT_RETRY_WINDOW: Duration = Duration::from_secs(30);
+
+/// Outcome of reporting a failed connection attempt to one of a neighbour's endpoints.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EndpointOutcome {
+    /// Another known endpoint should be tried next.
+    Retry(SocketAddrV4),
+    /// Every known endpoint has failed within the retry window.
+    Exhausted,
+}
+
+/// Last-write-wins snapshot of a neighbour's membership state, as exchanged by the
+/// anti-entropy protocol. `last_seen_secs_ago` is relative rather than absolute since
+/// `Instant`s are only meaningful on the node that recorded them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipEntry {
+    pub version: u64,
+    pub last_seen_secs_ago: u64,
+    pub alive: bool,
+}
+
+/// Bitflags a node advertises about itself during the `Register` handshake, so peers can
+/// tell whether it supports an optional feature before relying on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Services(u64);
+
+impl Services {
+    const SUSPECT_GOSSIP: u64 = 1 << 0;
+
+    pub fn none() -> Self {
+        Services(0)
+    }
+
+    pub fn with_suspect_gossip(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.0 |= Self::SUSPECT_GOSSIP;
+        } else {
+            self.0 &= !Self::SUSPECT_GOSSIP;
+        }
+        self
+    }
+
+    /// Returns `true` if every flag set in `other` is also set in `self`.
+    pub fn includes(&self, other: Services) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
 pub enum Charge {
     Connection,
     Reply,
@@ -41,19 +107,95 @@ impl Suspicion {
 
 #[derive(Debug)]
 struct Neighbour {
+    /// Known endpoints for this neighbour, most-preferred first. Always has at
+    /// least one entry once the neighbour is registered.
+    addresses: VecDeque<SocketAddrV4>,
+    failed_endpoints: HashSet<SocketAddrV4>,
+    first_failure: Option<Instant>,
     suspicion: Suspicion,
     suspected_by: HashSet<SocketAddrV4>,
     online: bool,
+    /// Last-write-wins membership bookkeeping, reconciled across the mesh via anti-entropy.
+    last_seen: Instant,
+    version: u64,
+    alive: bool,
+    /// Protocol version and capabilities the neighbour advertised in its `Register`
+    /// handshake. `None` until that handshake has happened.
+    protocol: Option<(u32, Services)>,
 }
 
 impl Default for Neighbour {
     fn default() -> Self {
         Neighbour {
+            addresses: VecDeque::new(),
+            failed_endpoints: HashSet::new(),
+            first_failure: None,
             suspicion: Suspicion::default(),
             suspected_by: HashSet::new(),
             online: true,
+            last_seen: Instant::now(),
+            version: 0,
+            alive: true,
+            protocol: None,
+        }
+    }
+}
+
+impl Neighbour {
+    /// Weight used for weighted-random gossiper selection. Reliable
+    /// neighbours (few charges) are weighted higher, but the weight never
+    /// drops below 1 so every online neighbour is still occasionally probed.
+    fn weight(&self) -> u32 {
+        let charges = u32::from(self.suspicion.connection) + u32::from(self.suspicion.reply);
+        1 + MAX_CHARGES.saturating_sub(charges)
+    }
+
+    /// Moves `addr` to the front of the known endpoints, evicting the oldest
+    /// one once the cap is exceeded.
+    fn remember_endpoint(&mut self, addr: SocketAddrV4) {
+        self.addresses.retain(|a| *a != addr);
+        self.addresses.push_front(addr);
+        self.addresses.truncate(MAX_ENDPOINTS);
+    }
+
+    fn current_endpoint(&self) -> Option<SocketAddrV4> {
+        self.addresses.front().copied()
+    }
+
+    /// Marks the neighbour as heard-from right now, bumping its version so the update
+    /// wins any future last-write-wins comparison.
+    fn touch(&mut self) {
+        self.last_seen = Instant::now();
+        self.alive = true;
+        self.version += 1;
+    }
+
+    fn to_entry(&self) -> MembershipEntry {
+        MembershipEntry {
+            version: self.version,
+            last_seen_secs_ago: self.last_seen.elapsed().as_secs(),
+            alive: self.alive,
         }
     }
+
+    /// Returns `true` if `entry` is at least as fresh as this neighbour's own record.
+    fn is_fresher(&self, entry: &MembershipEntry) -> bool {
+        match entry.version.cmp(&self.version) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => {
+                entry.last_seen_secs_ago <= self.last_seen.elapsed().as_secs()
+            }
+            std::cmp::Ordering::Less => false,
+        }
+    }
+
+    /// Adopts a remote membership record. Local-only bookkeeping (suspicion, endpoints)
+    /// is left untouched.
+    fn adopt(&mut self, entry: &MembershipEntry) {
+        self.version = entry.version;
+        self.alive = entry.alive;
+        self.last_seen = Instant::now() - Duration::from_secs(entry.last_seen_secs_ago);
+    }
 }
 
 #[derive(Debug)]
@@ -70,9 +212,161 @@ impl Neighbourhood {
         Self(HashMap::new())
     }
 
-    /// Add a new neighbour evicting existing neighbour with the same address
+    /// Add a new neighbour, or re-confirm `neighbour` as a known endpoint of an existing
+    /// entry. Unlike a plain insert, this never wipes an existing neighbour's suspicion
+    /// history or other endpoints.
     pub fn register(&mut self, neighbour: SocketAddrV4) {
-        self.0.insert(neighbour, Neighbour::default());
+        self.0
+            .entry(neighbour)
+            .or_default()
+            .remember_endpoint(neighbour);
+    }
+
+    /// Records `endpoint` as an additional, already-known-good way to reach `neighbour`,
+    /// making it the preferred endpoint. Other endpoints already on file stay, except that
+    /// the oldest one is evicted once more than `MAX_ENDPOINTS` are known. Unlike `register`,
+    /// `endpoint` need not equal `neighbour` - this is how a second candidate address for
+    /// an existing neighbour actually gets learned (e.g. an alternate address advertised
+    /// in the `Register` handshake).
+    pub fn remember_endpoint(&mut self, neighbour: SocketAddrV4, endpoint: SocketAddrV4) {
+        self.0
+            .entry(neighbour)
+            .or_default()
+            .remember_endpoint(endpoint);
+    }
+
+    /// Returns the endpoint that should currently be used to reach `neighbour`, if any.
+    pub fn current_endpoint(&self, neighbour: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.0.get(&neighbour).and_then(Neighbour::current_endpoint)
+    }
+
+    /// Finds the neighbour whose known endpoints include `endpoint`.
+    pub fn find_by_endpoint(&self, endpoint: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.0
+            .iter()
+            .find(|(_, n)| n.addresses.contains(&endpoint))
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Records that `endpoint` failed to connect for `neighbour`, rotating it to the back
+    /// of the known endpoints so the next one gets a turn. Returns the endpoint to retry
+    /// with next, or `Exhausted` once every known endpoint has failed within the retry window.
+    pub fn endpoint_failed(
+        &mut self,
+        neighbour: SocketAddrV4,
+        endpoint: SocketAddrV4,
+    ) -> EndpointOutcome {
+        let Some(n) = self.0.get_mut(&neighbour) else {
+            return EndpointOutcome::Exhausted;
+        };
+
+        let window_expired = match n.first_failure {
+            Some(first) => first.elapsed() > ENDPOINT_RETRY_WINDOW,
+            None => true,
+        };
+        if window_expired {
+            n.failed_endpoints.clear();
+            n.first_failure = Some(Instant::now());
+        }
+        n.failed_endpoints.insert(endpoint);
+
+        if let Some(pos) = n.addresses.iter().position(|a| *a == endpoint) {
+            if let Some(addr) = n.addresses.remove(pos) {
+                n.addresses.push_back(addr);
+            }
+        }
+
+        if n.addresses.iter().all(|a| n.failed_endpoints.contains(a)) {
+            n.failed_endpoints.clear();
+            n.first_failure = None;
+            EndpointOutcome::Exhausted
+        } else {
+            EndpointOutcome::Retry(n.current_endpoint().unwrap_or(endpoint))
+        }
+    }
+
+    /// Clears any recorded endpoint failures for `neighbour` after a successful connection.
+    pub fn endpoint_succeeded(&mut self, neighbour: SocketAddrV4) {
+        if let Some(n) = self.0.get_mut(&neighbour) {
+            n.failed_endpoints.clear();
+            n.first_failure = None;
+        }
+    }
+
+    /// Records that `neighbour` was just heard from, refreshing its membership record.
+    /// Does nothing for a neighbour we haven't registered - an inbound message's `src` is
+    /// attacker-controlled, so it must never be enough on its own to admit a new member.
+    /// Membership is only ever created via `register`/`remember_endpoint`/`negotiate`.
+    pub fn touch(&mut self, neighbour: SocketAddrV4) {
+        self.0.entry(neighbour).and_modify(Neighbour::touch);
+    }
+
+    /// Stores the protocol version and capabilities `neighbour` advertised in its
+    /// `Register` handshake.
+    pub fn negotiate(&mut self, neighbour: SocketAddrV4, version: u32, services: Services) {
+        let n = self.0.entry(neighbour).or_default();
+        if n.addresses.is_empty() {
+            n.remember_endpoint(neighbour);
+        }
+        n.protocol = Some((version, services));
+    }
+
+    /// Returns `true` if `neighbour` has advertised every capability in `required`.
+    /// Unknown neighbours (no handshake yet) are conservatively reported as unsupporting.
+    pub fn supports(&self, neighbour: &SocketAddrV4, required: Services) -> bool {
+        self.0
+            .get(neighbour)
+            .and_then(|n| n.protocol)
+            .is_some_and(|(_, services)| services.includes(required))
+    }
+
+    /// Marks neighbours dead once they haven't been heard from within `timeout`.
+    pub fn sweep_expired(&mut self, timeout: Duration) {
+        for n in self.0.values_mut() {
+            if n.alive && n.last_seen.elapsed() > timeout {
+                n.alive = false;
+                n.version += 1;
+            }
+        }
+    }
+
+    /// Builds a digest of this node's membership view, for anti-entropy exchange.
+    pub fn digest(&self) -> HashMap<SocketAddrV4, MembershipEntry> {
+        self.0
+            .iter()
+            .map(|(addr, n)| (*addr, n.to_entry()))
+            .collect()
+    }
+
+    /// Merges a remote digest into this node's membership view, keeping the higher
+    /// version (and, on a tie, the more recent `last_seen`) for each entry. Returns the
+    /// entries the sender is missing or holds a stale copy of, so they can catch up.
+    pub fn merge_digest(
+        &mut self,
+        incoming: HashMap<SocketAddrV4, MembershipEntry>,
+    ) -> HashMap<SocketAddrV4, MembershipEntry> {
+        let corrections: HashMap<SocketAddrV4, MembershipEntry> = self
+            .0
+            .iter()
+            .filter(|(addr, n)| match incoming.get(*addr) {
+                Some(remote) => !n.is_fresher(remote),
+                None => true,
+            })
+            .map(|(addr, n)| (*addr, n.to_entry()))
+            .collect();
+
+        for (addr, entry) in incoming {
+            // Route through `register` rather than a bare `entry().or_default()` so a
+            // neighbour learned only from a peer's digest still ends up with a usable
+            // endpoint (itself), instead of a half-initialised entry with no address.
+            self.register(addr);
+            let n = self.0.get_mut(&addr).expect("just registered");
+            if n.is_fresher(&entry) {
+                n.adopt(&entry);
+            }
+        }
+
+        corrections
     }
 
     /// Accuse a neighbour of `Charge`. If neighbour is accused of enough charges, they
@@ -118,12 +412,38 @@ impl Neighbourhood {
             .collect()
     }
 
-    /// Select neighbours to gossip with. Exclude neighbours that are considered suspicious by majority of the neighbourhood
-    pub fn select_gossipers(&self) -> Vec<SocketAddrV4> {
-        self.0
+    /// Select up to `fanout` neighbours to gossip with, weighted by reliability so that
+    /// flaky peers are picked less often than steady ones. Neighbours that are considered
+    /// suspicious by majority of the neighbourhood are excluded entirely.
+    ///
+    /// Implemented as a weighted draw without replacement: sum the remaining weights, draw
+    /// a uniform value in `[0, total)`, walk the cumulative sum to find the peer it lands on,
+    /// remove it from the pool, and repeat until `fanout` peers are chosen or the pool runs out.
+    pub fn select_gossipers(&self, fanout: usize) -> Vec<SocketAddrV4> {
+        let mut pool: Vec<(SocketAddrV4, u32)> = self
+            .0
             .iter()
-            .filter_map(|(a, n)| n.online.then_some(*a))
-            .collect()
+            .filter_map(|(a, n)| (n.online && n.alive).then_some((*a, n.weight())))
+            .collect();
+
+        let mut rng = rand::thread_rng();
+        let mut chosen = Vec::with_capacity(fanout.min(pool.len()));
+        while !pool.is_empty() && chosen.len() < fanout {
+            let total: u32 = pool.iter().map(|(_, weight)| weight).sum();
+            let mut draw = rng.gen_range(0..total);
+            let idx = pool
+                .iter()
+                .position(|(_, weight)| match draw.checked_sub(*weight) {
+                    Some(remainder) => {
+                        draw = remainder;
+                        false
+                    }
+                    None => true,
+                })
+                .unwrap_or(pool.len() - 1);
+            chosen.push(pool.swap_remove(idx).0);
+        }
+        chosen
     }
 
     pub fn get_all_neighbours(&self) -> Vec<SocketAddrV4> {
@@ -134,3 +454,264 @@ impl Neighbourhood {
         self.0.contains_key(neighbour)
     }
 }
+
+#[cfg(test)]
+mod test_support {
+    use std::net::SocketAddrV4;
+
+    /// Builds a loopback address on `port`, used throughout this module's tests as a
+    /// stand-in for a neighbour's address.
+    pub fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new("127.0.0.1".parse().unwrap(), port)
+    }
+}
+
+#[cfg(test)]
+mod merge_digest_tests {
+    use super::test_support::addr;
+    use super::*;
+
+    fn entry(version: u64, last_seen_secs_ago: u64, alive: bool) -> MembershipEntry {
+        MembershipEntry {
+            version,
+            last_seen_secs_ago,
+            alive,
+        }
+    }
+
+    #[test]
+    fn higher_version_wins_regardless_of_last_seen() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.0.get_mut(&addr(1)).unwrap().version = 1;
+
+        let mut incoming = HashMap::new();
+        incoming.insert(addr(1), entry(2, 1000, false));
+        n.merge_digest(incoming);
+
+        let adopted = n.0.get(&addr(1)).unwrap();
+        assert_eq!(adopted.version, 2);
+        assert!(!adopted.alive);
+    }
+
+    #[test]
+    fn lower_version_is_rejected() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.0.get_mut(&addr(1)).unwrap().version = 5;
+
+        let mut incoming = HashMap::new();
+        incoming.insert(addr(1), entry(1, 0, false));
+        n.merge_digest(incoming);
+
+        assert_eq!(n.0.get(&addr(1)).unwrap().version, 5);
+    }
+
+    #[test]
+    fn equal_version_breaks_tie_on_more_recent_last_seen() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        {
+            let local = n.0.get_mut(&addr(1)).unwrap();
+            local.version = 1;
+            local.last_seen = Instant::now() - Duration::from_secs(10);
+        }
+
+        let mut incoming = HashMap::new();
+        incoming.insert(addr(1), entry(1, 0, false));
+        n.merge_digest(incoming);
+
+        assert!(!n.0.get(&addr(1)).unwrap().alive);
+    }
+
+    #[test]
+    fn equal_version_keeps_local_when_incoming_is_staler() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        {
+            let local = n.0.get_mut(&addr(1)).unwrap();
+            local.version = 1;
+            local.last_seen = Instant::now();
+        }
+
+        let mut incoming = HashMap::new();
+        incoming.insert(addr(1), entry(1, 10, false));
+        n.merge_digest(incoming);
+
+        assert!(n.0.get(&addr(1)).unwrap().alive);
+    }
+
+    #[test]
+    fn unknown_remote_neighbour_is_adopted_with_a_usable_endpoint() {
+        let mut n = Neighbourhood::new();
+        let mut incoming = HashMap::new();
+        incoming.insert(addr(1), entry(3, 0, true));
+        n.merge_digest(incoming);
+
+        assert_eq!(n.0.get(&addr(1)).unwrap().version, 3);
+        assert_eq!(n.current_endpoint(addr(1)), Some(addr(1)));
+    }
+
+    #[test]
+    fn corrections_contain_entries_the_sender_is_missing_or_stale_on() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.0.get_mut(&addr(1)).unwrap().version = 7;
+
+        let corrections = n.merge_digest(HashMap::new());
+        assert_eq!(corrections.get(&addr(1)).unwrap().version, 7);
+    }
+}
+
+#[cfg(test)]
+mod select_gossipers_tests {
+    use super::test_support::addr;
+    use super::*;
+
+    #[test]
+    fn never_returns_more_than_fanout() {
+        let mut n = Neighbourhood::new();
+        for port in 1..=10 {
+            n.register(addr(port));
+        }
+        assert_eq!(n.select_gossipers(3).len(), 3);
+    }
+
+    #[test]
+    fn returns_every_eligible_neighbour_once_fanout_exceeds_pool_size() {
+        let mut n = Neighbourhood::new();
+        for port in 1..=4 {
+            n.register(addr(port));
+        }
+        let mut chosen = n.select_gossipers(100);
+        chosen.sort();
+        let mut expected: Vec<_> = (1..=4).map(addr).collect();
+        expected.sort();
+        assert_eq!(chosen, expected);
+    }
+
+    #[test]
+    fn excludes_offline_and_dead_neighbours() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.register(addr(2));
+        n.register(addr(3));
+        n.0.get_mut(&addr(2)).unwrap().online = false;
+        n.0.get_mut(&addr(3)).unwrap().alive = false;
+
+        let chosen = n.select_gossipers(10);
+        assert_eq!(chosen, vec![addr(1)]);
+    }
+
+    #[test]
+    fn empty_neighbourhood_yields_nothing() {
+        let n = Neighbourhood::new();
+        assert!(n.select_gossipers(5).is_empty());
+    }
+
+    #[test]
+    fn weight_favors_a_reliable_neighbour_over_a_heavily_charged_one() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.register(addr(2));
+        for _ in 0..3 {
+            n.accuse(addr(2), Charge::Connection);
+        }
+
+        let wins = (0..2000)
+            .filter(|_| n.select_gossipers(1) == vec![addr(1)])
+            .count();
+
+        // addr(1) has weight 4 (no charges) against addr(2)'s weight 1 (maxed out
+        // charges), so it should win the draw ~80% of the time. Assert a wide margin
+        // above chance to keep this from flaking while still exercising the weighting.
+        assert!(
+            wins > 1200,
+            "expected the uncharged neighbour to win well over half the draws, got {wins}/2000"
+        );
+    }
+}
+
+#[cfg(test)]
+mod endpoint_failed_tests {
+    use super::test_support::addr;
+    use super::*;
+
+    #[test]
+    fn retries_the_next_known_endpoint_before_exhausting() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.remember_endpoint(addr(1), addr(2));
+
+        assert_eq!(
+            n.endpoint_failed(addr(1), addr(1)),
+            EndpointOutcome::Retry(addr(2))
+        );
+    }
+
+    #[test]
+    fn exhausts_once_every_known_endpoint_has_failed() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.remember_endpoint(addr(1), addr(2));
+
+        assert_eq!(
+            n.endpoint_failed(addr(1), addr(1)),
+            EndpointOutcome::Retry(addr(2))
+        );
+        assert_eq!(n.endpoint_failed(addr(1), addr(2)), EndpointOutcome::Exhausted);
+    }
+
+    #[test]
+    fn endpoint_succeeded_clears_recorded_failures_so_exhaustion_resets() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.remember_endpoint(addr(1), addr(2));
+        n.endpoint_failed(addr(1), addr(1));
+        n.endpoint_succeeded(addr(1));
+
+        // Without the success in between, failing addr(2) as well would be `Exhausted`.
+        assert_eq!(
+            n.endpoint_failed(addr(1), addr(2)),
+            EndpointOutcome::Retry(addr(1))
+        );
+    }
+
+    #[test]
+    fn unknown_neighbour_is_reported_exhausted() {
+        let mut n = Neighbourhood::new();
+        assert_eq!(n.endpoint_failed(addr(1), addr(1)), EndpointOutcome::Exhausted);
+    }
+}
+
+#[cfg(test)]
+mod negotiate_tests {
+    use super::test_support::addr;
+    use super::*;
+
+    #[test]
+    fn unregistered_neighbour_supports_nothing() {
+        let n = Neighbourhood::new();
+        assert!(!n.supports(&addr(1), Services::none().with_suspect_gossip(true)));
+    }
+
+    #[test]
+    fn registered_but_not_yet_negotiated_neighbour_supports_nothing() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        assert!(!n.supports(&addr(1), Services::none().with_suspect_gossip(true)));
+    }
+
+    #[test]
+    fn negotiated_neighbour_is_gated_on_its_advertised_services() {
+        let mut n = Neighbourhood::new();
+        n.register(addr(1));
+        n.negotiate(addr(1), 1, Services::none().with_suspect_gossip(false));
+
+        let required = Services::none().with_suspect_gossip(true);
+        assert!(!n.supports(&addr(1), required));
+
+        n.negotiate(addr(1), 1, Services::none().with_suspect_gossip(true));
+        assert!(n.supports(&addr(1), required));
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashSet;
 use std::vec;
-use std::{collections::HashMap, net::SocketAddrV4};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddrV4,
+};
 
 use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
@@ -8,9 +11,61 @@ use tokio::sync::mpsc::{Receiver, Sender};
 use tracing::{debug, info, instrument, warn, Level};
 
 use crate::neighbourhood::Charge;
+use crate::neighbourhood::EndpointOutcome;
+use crate::neighbourhood::MembershipEntry;
 use crate::neighbourhood::Neighbourhood;
+use crate::neighbourhood::Services;
+use crate::neighbourhood::DEFAULT_FANOUT;
 
 const STALE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a neighbour can go unheard-from before the membership sweep marks it dead.
+const MEMBERSHIP_TIMEOUT: Duration = Duration::from_secs(300);
+/// Wire protocol version this build speaks, advertised in the `Register` handshake.
+const PROTOCOL_VERSION: u32 = 1;
+/// How many gossip message ids to remember before evicting the oldest ones.
+const SEEN_CACHE_CAPACITY: usize = 4096;
+
+/// Stable identifier of a `GossipRandom` message, used to detect duplicates
+/// as they are flooded through the mesh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GossipId {
+    origin: SocketAddrV4,
+    seq: u32,
+}
+
+/// Bounded set of seen `GossipId`s. Oldest entries are evicted once the
+/// cache is full, so memory use stays flat regardless of how long the node
+/// has been running.
+#[derive(Debug)]
+struct SeenCache {
+    capacity: usize,
+    order: VecDeque<GossipId>,
+    ids: HashSet<GossipId>,
+}
+
+impl SeenCache {
+    fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Records `id` as seen. Returns `true` if it was already present.
+    fn insert(&mut self, id: GossipId) -> bool {
+        if !self.ids.insert(id) {
+            return true;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        false
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
@@ -34,27 +89,51 @@ pub enum Trigger {
     GossipSuspects,
     Strike(SocketAddrV4),
     CheckReplies,
+    SweepMembership,
+    AntiEntropy,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Payload {
-    Register,
-    RegisterOk { known: Vec<SocketAddrV4> },
-    GossipRandom { message: String },
+    Register {
+        alt_addrs: Vec<SocketAddrV4>,
+        version: u32,
+        services: Services,
+    },
+    RegisterOk {
+        known: Vec<SocketAddrV4>,
+        alt_addrs: Vec<SocketAddrV4>,
+        version: u32,
+        services: Services,
+    },
+    GossipRandom {
+        id: GossipId,
+        message: String,
+    },
     GossipRandomOk,
-    GossipSuspect { suspects: HashSet<SocketAddrV4> },
+    GossipSuspect {
+        suspects: HashSet<SocketAddrV4>,
+    },
     GossipSuspectOk,
+    AntiEntropy {
+        digest: HashMap<SocketAddrV4, MembershipEntry>,
+    },
+    AntiEntropyOk {
+        digest: HashMap<SocketAddrV4, MembershipEntry>,
+    },
 }
 
 impl Payload {
     fn requires_reply(&self) -> bool {
         match self {
-            Payload::Register
-            | Payload::GossipRandom { message: _ }
-            | Payload::GossipSuspect { suspects: _ } => true,
-            Payload::RegisterOk { known: _ }
+            Payload::Register { .. }
+            | Payload::GossipRandom { .. }
+            | Payload::GossipSuspect { suspects: _ }
+            | Payload::AntiEntropy { .. } => true,
+            Payload::RegisterOk { .. }
             | Payload::GossipRandomOk
-            | Payload::GossipSuspectOk => false,
+            | Payload::GossipSuspectOk
+            | Payload::AntiEntropyOk { .. } => false,
         }
     }
 }
@@ -63,21 +142,37 @@ impl Payload {
 pub struct Node {
     src: SocketAddrV4,
     cnt: u32,
+    gossip_seq: u32,
     neighbourhood: Neighbourhood,
     rx: Receiver<Event>,
     tx: Sender<Message>,
     awaiting_reply: HashMap<u32, (SocketAddrV4, Instant)>,
+    seen: SeenCache,
+    /// Additional addresses this node can be reached at, advertised to peers during the
+    /// `Register` handshake alongside `src` so they can learn it as an alternate endpoint.
+    alt_addrs: Vec<SocketAddrV4>,
+    /// Capabilities this node advertises to peers during the `Register` handshake.
+    services: Services,
 }
 
 impl Node {
-    pub fn new(addr: SocketAddrV4, rx: Receiver<Event>, tx: Sender<Message>) -> Self {
+    pub fn new(
+        addr: SocketAddrV4,
+        alt_addrs: Vec<SocketAddrV4>,
+        rx: Receiver<Event>,
+        tx: Sender<Message>,
+    ) -> Self {
         Node {
             src: addr,
             cnt: 0,
+            gossip_seq: 0,
             neighbourhood: Neighbourhood::new(),
             rx,
             tx,
             awaiting_reply: HashMap::new(),
+            seen: SeenCache::new(SEEN_CACHE_CAPACITY),
+            alt_addrs,
+            services: Services::none().with_suspect_gossip(true),
         }
     }
 
@@ -104,15 +199,39 @@ impl Node {
         match trigger {
             Trigger::Register(dst) => {
                 self.neighbourhood.register(dst);
-                vec![self.message(dst, None, Payload::Register)]
+                vec![self.message(
+                    dst,
+                    None,
+                    Payload::Register {
+                        alt_addrs: self.alt_addrs.clone(),
+                        version: PROTOCOL_VERSION,
+                        services: self.services,
+                    },
+                )]
             }
             Trigger::GossipRandom => self.gossip(),
             Trigger::GossipSuspects => self.gossip_suspects(),
             Trigger::Strike(addr) => {
                 warn!("Received a strike for {}", addr);
-                self.neighbourhood.accuse(addr, Charge::Connection);
+                match self.neighbourhood.find_by_endpoint(addr) {
+                    Some(neighbour) => match self.neighbourhood.endpoint_failed(neighbour, addr) {
+                        EndpointOutcome::Retry(next) => {
+                            debug!("Switching {} to alternate endpoint {}", neighbour, next);
+                        }
+                        EndpointOutcome::Exhausted => {
+                            warn!("All known endpoints for {} failed", neighbour);
+                            self.neighbourhood.accuse(neighbour, Charge::Connection);
+                        }
+                    },
+                    None => self.neighbourhood.accuse(addr, Charge::Connection),
+                }
                 vec![]
             }
+            Trigger::SweepMembership => {
+                self.neighbourhood.sweep_expired(MEMBERSHIP_TIMEOUT);
+                vec![]
+            }
+            Trigger::AntiEntropy => self.anti_entropy(),
             Trigger::CheckReplies => {
                 let stale_keys: Vec<_> = self
                     .awaiting_reply
@@ -139,18 +258,42 @@ impl Node {
 
     fn handle_message(&mut self, msg: Message) -> Vec<Message> {
         self.neighbourhood.dismiss(msg.src, Charge::Connection);
+        self.neighbourhood.endpoint_succeeded(msg.src);
+        self.neighbourhood.touch(msg.src);
         match msg.payload {
-            Payload::Register => {
+            Payload::Register {
+                alt_addrs,
+                version,
+                services,
+            } => {
                 let neighbours: Vec<SocketAddrV4> = self.neighbourhood.get_all_neighbours();
                 self.neighbourhood.register(msg.src);
+                for alt in alt_addrs {
+                    self.neighbourhood.remember_endpoint(msg.src, alt);
+                }
+                self.neighbourhood.negotiate(msg.src, version, services);
                 vec![self.message(
                     msg.src,
                     Some(msg.id),
-                    Payload::RegisterOk { known: neighbours },
+                    Payload::RegisterOk {
+                        known: neighbours,
+                        alt_addrs: self.alt_addrs.clone(),
+                        version: PROTOCOL_VERSION,
+                        services: self.services,
+                    },
                 )]
             }
-            Payload::RegisterOk { known } => {
+            Payload::RegisterOk {
+                known,
+                alt_addrs,
+                version,
+                services,
+            } => {
                 self.handle_reply(msg.reply_to, msg.src);
+                for alt in alt_addrs {
+                    self.neighbourhood.remember_endpoint(msg.src, alt);
+                }
+                self.neighbourhood.negotiate(msg.src, version, services);
                 let to_register: Vec<SocketAddrV4> = known
                     .into_iter()
                     .filter(|n| !self.neighbourhood.is_registered(n))
@@ -158,14 +301,43 @@ impl Node {
                 let mut messages: Vec<Message> = Vec::with_capacity(to_register.len());
                 for addr in to_register {
                     self.neighbourhood.register(addr);
-                    messages.push(self.message(addr, None, Payload::Register));
+                    messages.push(self.message(
+                        addr,
+                        None,
+                        Payload::Register {
+                            alt_addrs: self.alt_addrs.clone(),
+                            version: PROTOCOL_VERSION,
+                            services: self.services,
+                        },
+                    ));
                 }
                 debug!("My neighbourhood is {:#?}", self.neighbourhood);
                 messages
             }
-            Payload::GossipRandom { message } => {
+            Payload::GossipRandom { id, message } => {
+                let ack = self.message(msg.src, Some(msg.id), Payload::GossipRandomOk);
+                if self.seen.insert(id) {
+                    debug!("Already seen gossip {:?}, not forwarding", id);
+                    return vec![ack];
+                }
                 info!("Message from {}: {}", msg.src, message);
-                vec![self.message(msg.src, Some(msg.id), Payload::GossipRandomOk)]
+                let mut messages = vec![ack];
+                for dst in self
+                    .neighbourhood
+                    .select_gossipers(DEFAULT_FANOUT)
+                    .into_iter()
+                    .filter(|dst| *dst != msg.src && *dst != id.origin)
+                {
+                    messages.push(self.message(
+                        dst,
+                        None,
+                        Payload::GossipRandom {
+                            id,
+                            message: message.clone(),
+                        },
+                    ));
+                }
+                messages
             }
             Payload::GossipSuspect { suspects } => {
                 debug!(
@@ -179,6 +351,21 @@ impl Node {
                 self.handle_reply(msg.reply_to, msg.src);
                 vec![]
             }
+            Payload::AntiEntropy { digest } => {
+                let corrections = self.neighbourhood.merge_digest(digest);
+                vec![self.message(
+                    msg.src,
+                    Some(msg.id),
+                    Payload::AntiEntropyOk {
+                        digest: corrections,
+                    },
+                )]
+            }
+            Payload::AntiEntropyOk { digest } => {
+                self.handle_reply(msg.reply_to, msg.src);
+                self.neighbourhood.merge_digest(digest);
+                vec![]
+            }
         }
     }
 
@@ -223,7 +410,13 @@ impl Node {
             debug!("Not suspecting anyone of treason");
             return vec![];
         }
-        let gossipers: Vec<_> = self.neighbourhood.select_gossipers();
+        let required = Services::none().with_suspect_gossip(true);
+        let gossipers: Vec<_> = self
+            .neighbourhood
+            .select_gossipers(DEFAULT_FANOUT)
+            .into_iter()
+            .filter(|dst| self.neighbourhood.supports(dst, required))
+            .collect();
         debug!(
             "Time to gossip suspects! Gossiping with {} neighbours",
             gossipers.len()
@@ -242,35 +435,165 @@ impl Node {
     }
 
     fn gossip(&mut self) -> Vec<Message> {
-        let gossipers: Vec<_> = self.neighbourhood.select_gossipers();
+        let gossipers: Vec<_> = self.neighbourhood.select_gossipers(DEFAULT_FANOUT);
         info!(
             "Time to gossip! Gossiping with {} neighbours",
             gossipers.len()
         );
+        self.gossip_seq += 1;
+        let id = GossipId {
+            origin: self.src,
+            seq: self.gossip_seq,
+        };
+        self.seen.insert(id);
+        let message = format!("Some spicy scoop from {}", self.src);
         let mut messages = Vec::with_capacity(gossipers.len());
         for dst in gossipers {
             messages.push(self.message(
                 dst,
                 None,
                 Payload::GossipRandom {
-                    message: format!("Some spicy scoop from {}", self.src),
+                    id,
+                    message: message.clone(),
                 },
             ));
         }
         messages
     }
 
+    /// Exchanges a membership digest with a single random neighbour so that liveness and
+    /// version information self-heals after partitions, instead of relying solely on
+    /// direct messages and suspicion votes.
+    fn anti_entropy(&mut self) -> Vec<Message> {
+        let digest = self.neighbourhood.digest();
+        self.neighbourhood
+            .select_gossipers(1)
+            .into_iter()
+            .map(|dst| {
+                self.message(
+                    dst,
+                    None,
+                    Payload::AntiEntropy {
+                        digest: digest.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Builds a `Message` addressed to the neighbour `dst`, resolving it to whichever of
+    /// its known endpoints should currently be used on the wire.
     fn message(&mut self, dst: SocketAddrV4, reply_to: Option<u32>, payload: Payload) -> Message {
         self.cnt += 1;
         if payload.requires_reply() {
             self.awaiting_reply.insert(self.cnt, (dst, Instant::now()));
         }
+        let wire_dst = self.neighbourhood.current_endpoint(dst).unwrap_or(dst);
         Message {
             src: self.src,
-            dst,
+            dst: wire_dst,
             id: self.cnt,
             reply_to,
             payload,
         }
     }
 }
+
+#[cfg(test)]
+mod seen_cache_tests {
+    use super::*;
+
+    fn id(seq: u32) -> GossipId {
+        GossipId {
+            origin: SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1),
+            seq,
+        }
+    }
+
+    #[test]
+    fn insert_reports_new_ids_as_unseen_and_repeats_as_seen() {
+        let mut cache = SeenCache::new(4);
+        assert!(!cache.insert(id(1)));
+        assert!(cache.insert(id(1)));
+    }
+
+    #[test]
+    fn evicts_the_oldest_id_once_capacity_is_exceeded() {
+        let mut cache = SeenCache::new(2);
+        assert!(!cache.insert(id(1)));
+        assert!(!cache.insert(id(2)));
+        assert!(!cache.insert(id(3)));
+        // id(1) was evicted to make room for id(3), so it reads as unseen again.
+        assert!(!cache.insert(id(1)));
+    }
+}
+
+#[cfg(test)]
+mod gossip_forwarding_tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    fn node_with_neighbours(src: SocketAddrV4, neighbours: &[SocketAddrV4]) -> Node {
+        let (_in_tx, in_rx) = channel(8);
+        let (out_tx, _out_rx) = channel(8);
+        let mut node = Node::new(src, vec![], in_rx, out_tx);
+        for n in neighbours {
+            node.handle_trigger(Trigger::Register(*n));
+        }
+        node
+    }
+
+    fn gossip(src: SocketAddrV4, dst: SocketAddrV4, origin: SocketAddrV4, seq: u32) -> Message {
+        Message {
+            src,
+            dst,
+            id: 1,
+            reply_to: None,
+            payload: Payload::GossipRandom {
+                id: GossipId { origin, seq },
+                message: "hi".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn forwarding_excludes_the_sender_and_the_message_origin() {
+        let me = addr(0);
+        let sender = addr(1);
+        let origin = addr(2);
+        let other = addr(3);
+        let mut node = node_with_neighbours(me, &[sender, origin, other]);
+
+        let replies = node.handle_message(gossip(sender, me, origin, 1));
+
+        let forwarded: Vec<_> = replies
+            .iter()
+            .filter(|m| matches!(m.payload, Payload::GossipRandom { .. }))
+            .map(|m| m.dst)
+            .collect();
+        assert_eq!(forwarded, vec![other]);
+    }
+
+    #[test]
+    fn a_duplicate_gossip_id_is_acked_but_not_forwarded_again() {
+        let me = addr(0);
+        let sender = addr(1);
+        let origin = addr(2);
+        let other = addr(3);
+        let mut node = node_with_neighbours(me, &[sender, origin, other]);
+
+        node.handle_message(gossip(sender, me, origin, 1));
+        let replies = node.handle_message(gossip(sender, me, origin, 1));
+
+        assert!(!replies
+            .iter()
+            .any(|m| matches!(m.payload, Payload::GossipRandom { .. })));
+        assert!(replies
+            .iter()
+            .any(|m| matches!(m.payload, Payload::GossipRandomOk)));
+    }
+}